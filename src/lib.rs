@@ -3,18 +3,38 @@ extern crate mopa;
 extern crate pulse;
 extern crate threadpool;
 extern crate fnv;
+extern crate parking_lot;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde_derive;
+#[cfg(feature = "serde")]
+extern crate serde_json;
 
-use std::any::TypeId;
+use std::any::{Any as StdAny, TypeId};
 use std::cell::RefCell;
 use std::collections::HashMap;
-use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+use parking_lot::{Condvar, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use mopa::Any;
 use pulse::{Pulse, Signal};
 use threadpool::ThreadPool;
+#[cfg(feature = "serde")]
+use serde::Serialize;
+#[cfg(feature = "serde")]
+use serde::de::DeserializeOwned;
 
-pub use storage::{Storage, StorageBase, VecStorage, HashMapStorage};
+pub use storage::{Storage, StorageBase, VecStorage, HashMapStorage, SparseSetStorage,
+                   FlaggedStorage, ComponentEvent};
+#[cfg(feature = "serde")]
+pub use saveload::{Snapshot, SerializeStorage};
 
 mod storage;
+#[cfg(feature = "serde")]
+mod saveload;
 
 /// Index generation. When a new entity is placed at the old index,
 /// it bumps the generation by 1. This allows to avoid using components
@@ -27,6 +47,7 @@ pub type Generation = i32;
 pub type Index = u32;
 /// Entity type, as seen by the user.
 #[derive(Clone, Copy, Debug, Hash, Eq, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Entity(Index, Generation);
 
 impl Entity {
@@ -71,19 +92,53 @@ pub trait Component: Any + Sized {
     type Storage: Storage<Self> + Any + Send + Sync;
 }
 
+/// A component type confined to the thread that owns its `Scheduler`.
+/// Unlike `Component`, `Storage` need not be `Send`/`Sync`, so this covers
+/// components wrapping handles that can't cross threads (GPU resources,
+/// `Rc`-based scene graphs, raw OS handles). Registered with
+/// `Scheduler::register_local` and only ever touched by `run_local*`
+/// systems, which run synchronously on the scheduler's own thread instead
+/// of being handed to the `ThreadPool`.
+pub trait LocalComponent: Any + Sized {
+    type Storage: Storage<Self> + Any;
+}
+
 trait StorageLock: Any + Send + Sync {
     fn del_slice(&self, &[Entity]);
+    fn clear_flags(&self);
 }
 
 mopafy!(StorageLock);
 
+/// Like `StorageLock`, but for `LocalComponent` storage. No `Send`/`Sync`
+/// bound, since it's never reachable from anywhere but the thread that
+/// registered it.
+trait LocalStorageLock: Any {
+    fn del(&self, entity: Entity);
+    fn clear_flags(&self);
+}
+
+mopafy!(LocalStorageLock);
+
+impl<S: StorageBase + Any> LocalStorageLock for RefCell<S> {
+    fn del(&self, entity: Entity) {
+        self.borrow_mut().del(entity);
+    }
+    fn clear_flags(&self) {
+        self.borrow_mut().clear_flags();
+    }
+}
+
 impl<S: StorageBase + Any + Send + Sync> StorageLock for RwLock<S> {
     fn del_slice(&self, entities: &[Entity]) {
-        let mut guard = self.write().unwrap();
+        let mut guard = self.write();
         for &e in entities.iter() {
             guard.del(e);
         }
     }
+    fn clear_flags(&self) {
+        self.write().clear_flags();
+    }
 }
 
 
@@ -92,6 +147,9 @@ impl<S: StorageBase + Any + Send + Sync> StorageLock for RwLock<S> {
 pub struct World {
     generations: RwLock<Vec<Generation>>,
     components: HashMap<TypeId, Box<StorageLock>>,
+    #[cfg(feature = "serde")]
+    serializers: HashMap<String, Box<SerializeStorage>>,
+    resources: RwLock<HashMap<TypeId, Box<StdAny + Send + Sync>>>,
 }
 
 
@@ -101,6 +159,32 @@ impl World {
         World {
             generations: RwLock::new(Vec::new()),
             components: HashMap::new(),
+            #[cfg(feature = "serde")]
+            serializers: HashMap::new(),
+            resources: RwLock::new(HashMap::new()),
+        }
+    }
+    /// Add a global resource, replacing any previous value of the same
+    /// type. Resources are singletons shared across the whole `World`
+    /// (delta time, input state, an RNG, ...) rather than per-entity data,
+    /// so they live in their own map instead of a `Storage`.
+    pub fn add_resource<R: StdAny + Send + Sync>(&self, resource: R) {
+        self.resources.write().insert(TypeId::of::<R>(), Box::new(resource));
+    }
+    /// Lock a resource for reading. The returned guard panics when
+    /// dereferenced if `R` was never added.
+    pub fn read_resource<'a, R: StdAny + Send + Sync>(&'a self) -> ResourceReadGuard<'a, R> {
+        ResourceReadGuard {
+            guard: self.resources.read(),
+            marker: PhantomData,
+        }
+    }
+    /// Lock a resource for writing. The returned guard panics when
+    /// dereferenced if `R` was never added.
+    pub fn write_resource<'a, R: StdAny + Send + Sync>(&'a self) -> ResourceWriteGuard<'a, R> {
+        ResourceWriteGuard {
+            guard: self.resources.write(),
+            marker: PhantomData,
         }
     }
     /// Register a new component type.
@@ -108,11 +192,22 @@ impl World {
         let any = RwLock::new(T::Storage::new());
         self.components.insert(TypeId::of::<T>(), Box::new(any));
     }
+    /// Register a component type that can additionally be saved and loaded
+    /// through `save`/`load`, under the given stable `name`. A `TypeId`
+    /// isn't stable across builds, so snapshots key components by this
+    /// user-supplied name instead.
+    #[cfg(feature = "serde")]
+    pub fn register_serializable<T>(&mut self, name: &'static str) where
+        T: Component, T::Storage: Storage<T>, T: Serialize + DeserializeOwned
+    {
+        self.register::<T>();
+        self.serializers.insert(name.to_string(), saveload::serializer::<T>());
+    }
     /// Unregister a component type.
     pub fn unregister<T: Component>(&mut self) -> Option<T::Storage> {
         self.components.remove(&TypeId::of::<T>()).map(|boxed|
             match boxed.downcast::<RwLock<T::Storage>>() {
-                Ok(b) => (*b).into_inner().unwrap(),
+                Ok(b) => (*b).into_inner(),
                 Err(_) => panic!("Unable to downcast the storage type"),
             }
         )
@@ -123,21 +218,32 @@ impl World {
     }
     /// Lock a component for reading.
     pub fn read<'a, T: Component>(&'a self) -> RwLockReadGuard<'a, T::Storage> {
-        self.lock::<T>().read().unwrap()
+        self.lock::<T>().read()
     }
     /// Lock a component for writing.
     pub fn write<'a, T: Component>(&'a self) -> RwLockWriteGuard<'a, T::Storage> {
-        self.lock::<T>().write().unwrap()
+        self.lock::<T>().write()
+    }
+    /// Lock a component for reading without blocking. Returns `None` if
+    /// another system currently holds it for writing, instead of waiting.
+    pub fn try_read<'a, T: Component>(&'a self) -> Option<RwLockReadGuard<'a, T::Storage>> {
+        self.lock::<T>().try_read()
+    }
+    /// Lock a component for writing without blocking. Returns `None` if
+    /// another system currently holds it for reading or writing, instead of
+    /// waiting.
+    pub fn try_write<'a, T: Component>(&'a self) -> Option<RwLockWriteGuard<'a, T::Storage>> {
+        self.lock::<T>().try_write()
     }
     /// Return the entity iterator.
     pub fn entities<'a>(&'a self) -> EntityIter<'a> {
         EntityIter {
-            guard: self.generations.read().unwrap(),
+            guard: self.generations.read(),
             index: 0,
         }
     }
     fn find_next(&self, base: usize) -> Entity {
-        let gens = self.generations.read().unwrap();
+        let gens = self.generations.read();
         match gens.iter().enumerate().skip(base).find(|&(_, g)| *g <= 0) {
             Some((id, gen)) => Entity(id as Index, 1 - gen),
             None => Entity(gens.len() as Index, 1),
@@ -145,7 +251,72 @@ impl World {
     }
     /// Return the generations array locked for reading. Useful for debugging.
     pub fn get_generations<'a>(&'a self) -> RwLockReadGuard<'a, Vec<Generation>> {
-        self.generations.read().unwrap()
+        self.generations.read()
+    }
+    /// Snapshot every component registered with `register_serializable`,
+    /// plus the generation table, for saving or sending over the network.
+    #[cfg(feature = "serde")]
+    pub fn save(&self) -> Snapshot {
+        let components = self.serializers.iter()
+            .map(|(name, ser)| (name.clone(), ser.save(self)))
+            .collect();
+        Snapshot {
+            generations: self.generations.read().clone(),
+            components: components,
+        }
+    }
+    /// Restore a `Snapshot` taken by `save`. Every component name in the
+    /// snapshot must have a matching `register_serializable` call; unknown
+    /// names are ignored. Each restored component's storage is cleared
+    /// before the snapshot's values are inserted, so live entities that
+    /// aren't part of the snapshot don't keep stale components afterwards.
+    #[cfg(feature = "serde")]
+    pub fn load(&self, snapshot: Snapshot) {
+        *self.generations.write() = snapshot.generations;
+        for (name, values) in snapshot.components {
+            if let Some(ser) = self.serializers.get(&name) {
+                ser.load(self, values);
+            }
+        }
+    }
+}
+
+
+/// Read guard returned by `World::read_resource`.
+pub struct ResourceReadGuard<'a, R: 'a> {
+    guard: RwLockReadGuard<'a, HashMap<TypeId, Box<StdAny + Send + Sync>>>,
+    marker: PhantomData<&'a R>,
+}
+
+impl<'a, R: StdAny + Send + Sync> Deref for ResourceReadGuard<'a, R> {
+    type Target = R;
+    fn deref(&self) -> &R {
+        self.guard.get(&TypeId::of::<R>())
+            .unwrap_or_else(|| panic!("resource not found, did you forget to World::add_resource it?"))
+            .downcast_ref::<R>().unwrap()
+    }
+}
+
+/// Write guard returned by `World::write_resource`.
+pub struct ResourceWriteGuard<'a, R: 'a> {
+    guard: RwLockWriteGuard<'a, HashMap<TypeId, Box<StdAny + Send + Sync>>>,
+    marker: PhantomData<&'a mut R>,
+}
+
+impl<'a, R: StdAny + Send + Sync> Deref for ResourceWriteGuard<'a, R> {
+    type Target = R;
+    fn deref(&self) -> &R {
+        self.guard.get(&TypeId::of::<R>())
+            .unwrap_or_else(|| panic!("resource not found, did you forget to World::add_resource it?"))
+            .downcast_ref::<R>().unwrap()
+    }
+}
+
+impl<'a, R: StdAny + Send + Sync> DerefMut for ResourceWriteGuard<'a, R> {
+    fn deref_mut(&mut self) -> &mut R {
+        self.guard.get_mut(&TypeId::of::<R>())
+            .unwrap_or_else(|| panic!("resource not found, did you forget to World::add_resource it?"))
+            .downcast_mut::<R>().unwrap()
     }
 }
 
@@ -192,7 +363,7 @@ impl WorldArg {
     }
     /// Insert a new entity dynamically.
     pub fn insert(&self) -> Entity {
-        let mut app = self.app.write().unwrap();
+        let mut app = self.app.write();
         let ent = app.next;
         app.add_queue.push(ent);
         app.next = self.world.find_next(ent.get_id() + 1);
@@ -200,13 +371,13 @@ impl WorldArg {
     }
     /// Remove an entity dynamically.
     pub fn remove(&self, entity: Entity) {
-        let mut app = self.app.write().unwrap();
+        let mut app = self.app.write();
         app.sub_queue.push(entity);
     }
     /// Iterate dynamically added entities.
     pub fn new_entities<'a>(&'a self) -> NewEntityIter<'a> {
         NewEntityIter {
-            guard: self.app.read().unwrap(),
+            guard: self.app.read(),
             index: 0,
         }
     }
@@ -228,10 +399,50 @@ impl<'a> EntityBuilder<'a> {
 }
 
 
+/// The set of component types a system reads and writes, used by the
+/// scheduler to tell whether two systems may run concurrently. Two accesses
+/// conflict when they share a write, or a write of one overlaps a read of
+/// the other; disjoint reads never conflict with each other.
+#[derive(Clone, PartialEq)]
+struct Access {
+    writes: Vec<TypeId>,
+    reads: Vec<TypeId>,
+    /// Set for systems (raw `Scheduler::run` closures) that didn't declare
+    /// their component sets; conflicts with every other access, including
+    /// another exclusive one, so it always runs alone.
+    exclusive: bool,
+}
+
+impl Access {
+    fn with_sets(writes: Vec<TypeId>, reads: Vec<TypeId>) -> Access {
+        Access { writes: writes, reads: reads, exclusive: false }
+    }
+    fn exclusive() -> Access {
+        Access { writes: Vec::new(), reads: Vec::new(), exclusive: true }
+    }
+    fn conflicts(&self, other: &Access) -> bool {
+        if self.exclusive || other.exclusive {
+            return true;
+        }
+        self.writes.iter().any(|w| other.writes.contains(w) || other.reads.contains(w)) ||
+        self.reads.iter().any(|r| other.writes.contains(r))
+    }
+}
+
+/// Accesses of the systems currently dispatched to the thread pool. Guarded
+/// by a condvar so `dispatch` can block until a conflicting access clears,
+/// and `flush` can block until every in-flight system is done.
+struct RunningSet {
+    accesses: Mutex<Vec<Access>>,
+    cleared: Condvar,
+}
+
 pub struct Scheduler {
     world: Arc<World>,
     threads: ThreadPool,
     appendix: Arc<RwLock<Appendix>>,
+    running: Arc<RunningSet>,
+    locals: HashMap<TypeId, Box<LocalStorageLock>>,
 }
 
 impl Scheduler {
@@ -245,32 +456,81 @@ impl Scheduler {
             world: Arc::new(world),
             threads: ThreadPool::new(num_threads),
             appendix: Arc::new(RwLock::new(next)),
+            running: Arc::new(RunningSet {
+                accesses: Mutex::new(Vec::new()),
+                cleared: Condvar::new(),
+            }),
+            locals: HashMap::new(),
         }
     }
     pub fn get_world(&self) -> &World {
         &self.world
     }
-    pub fn run<F>(&mut self, functor: F) where
+    /// Register a thread-local component type. Unlike `World::register`,
+    /// `T::Storage` isn't required to be `Send`/`Sync`: its storage is
+    /// pinned to this `Scheduler`, and only ever touched by `run_local*`
+    /// systems running on this same thread, never by the `ThreadPool`.
+    pub fn register_local<T: LocalComponent>(&mut self) {
+        let cell = RefCell::new(T::Storage::new());
+        self.locals.insert(TypeId::of::<T>(), Box::new(cell));
+    }
+    fn lock_local<T: LocalComponent>(&self) -> &RefCell<T::Storage> {
+        let boxed = self.locals.get(&TypeId::of::<T>()).unwrap();
+        boxed.downcast_ref().unwrap()
+    }
+    /// Run `functor` once no currently in-flight system's access conflicts
+    /// with `access`. Returns as soon as `functor` has locked the
+    /// components it fetched, same as `run`; the system keeps executing
+    /// concurrently with whatever else the scheduler dispatches next, as
+    /// long as their accesses are disjoint. Call `flush` to wait for every
+    /// dispatched system to actually finish.
+    fn dispatch<F>(&mut self, access: Access, functor: F) where
         F: 'static + Send + FnOnce(WorldArg)
     {
+        let running = self.running.clone();
+        {
+            let mut guard = running.accesses.lock();
+            while guard.iter().any(|a| a.conflicts(&access)) {
+                running.cleared.wait(&mut guard);
+            }
+            guard.push(access.clone());
+        }
         let (signal, pulse) = Signal::new();
         let world = self.world.clone();
         let app = self.appendix.clone();
-        self.threads.execute(|| {
+        let done = running.clone();
+        self.threads.execute(move || {
             functor(WorldArg {
                 world: world,
                 pulse: RefCell::new(Some(pulse)),
                 app: app,
             });
+            let mut guard = done.accesses.lock();
+            if let Some(pos) = guard.iter().position(|a| *a == access) {
+                guard.remove(pos);
+            }
+            done.cleared.notify_all();
         });
         signal.wait().unwrap();
     }
+    /// Block until every system dispatched so far has finished running.
+    pub fn flush(&self) {
+        let mut guard = self.running.accesses.lock();
+        while !guard.is_empty() {
+            self.running.cleared.wait(&mut guard);
+        }
+    }
+    pub fn run<F>(&mut self, functor: F) where
+        F: 'static + Send + FnOnce(WorldArg)
+    {
+        self.dispatch(Access::exclusive(), functor);
+    }
     pub fn add_entity<'a>(&'a mut self) -> EntityBuilder<'a> {
-        let mut appendix = self.appendix.write().unwrap();
+        let mut appendix = self.appendix.write();
         let ent = appendix.next;
         assert!(ent.get_gen() > 0);
         if ent.get_gen() == 1 {
-            let mut gens = self.world.generations.write().unwrap();
+            let mut gens = self.world.generations.write();
             assert!(gens.len() == ent.get_id());
             gens.push(ent.get_gen());
         }
@@ -281,18 +541,22 @@ impl Scheduler {
         for boxed in self.world.components.values() {
             boxed.del_slice(&[entity]);
         }
-        let mut gens = self.world.generations.write().unwrap();
+        for boxed in self.locals.values() {
+            boxed.del(entity);
+        }
+        let mut gens = self.world.generations.write();
         let mut gen = &mut gens[entity.get_id() as usize];
         assert!(*gen > 0);
-        let mut appendix = self.appendix.write().unwrap();
+        let mut appendix = self.appendix.write();
         if entity.get_id() < appendix.next.get_id() {
             appendix.next = Entity(entity.0, *gen+1);
         }
         *gen *= -1;
     }
     pub fn rest(&self) {
-        let mut gens = self.world.generations.write().unwrap();
-        let mut app = self.appendix.write().unwrap();
+        self.flush();
+        let mut gens = self.world.generations.write();
+        let mut app = self.appendix.write();
         for ent in app.add_queue.drain(..) {
             while gens.len() <= ent.get_id() {
                 gens.push(0);
@@ -309,50 +573,460 @@ impl Scheduler {
             gens[ent.get_id()] *= -1;
         }
         app.next = next;
+        for boxed in self.world.components.values() {
+            boxed.clear_flags();
+        }
+        for boxed in self.locals.values() {
+            boxed.clear_flags();
+        }
     }
 }
 
 macro_rules! impl_run {
-    ($name:ident [$( $write:ident ),*] [$( $read:ident ),*]) => (impl Scheduler {
+    ($name:ident [$( $write:ident ),*] [$( $read:ident ),*] [$( $res:ident ),*]) => (impl Scheduler {
         #[allow(non_snake_case, unused_mut)]
         pub fn $name<
-            $($write:Component,)* $($read:Component,)*
-            F: 'static + Send + FnMut( $(&mut $write,)* $(&$read,)* )
+            $($write:Component,)* $($read:Component,)* $($res: StdAny + Send + Sync,)*
+            F: 'static + Send + FnMut( $(&mut $write,)* $(&$read,)* $(&$res,)* )
         >(&mut self, functor: F) {
-            self.run(|warg| {
+            let access = Access::with_sets(
+                vec![ $( TypeId::of::<$write>(), )* ],
+                vec![ $( TypeId::of::<$read>(), )* ],
+            );
+            self.dispatch(access, |warg| {
                 let mut fun = functor;
-                let ($(mut $write,)* $($read,)* entities) = warg.fetch(|w|
+                let ($(mut $write,)* $($read,)* $($res,)* entities) = warg.fetch(|w|
                     ($(w.write::<$write>(),)*
                      $(w.read::<$read>(),)*
+                     $(w.read_resource::<$res>(),)*
                        w.entities())
                 );
                 for ent in entities {
                     if let ( $( Some($write), )* $( Some($read), )* ) =
                         ( $( $write.get_mut(ent), )* $( $read.get(ent), )* ) {
-                        fun( $($write,)* $($read,)* );
+                        fun( $($write,)* $($read,)* $(&*$res,)* );
                     }
                 }
                 for ent in warg.new_entities() {
                     if let ( $( Some($write), )* $( Some($read), )* ) =
                         ( $( $write.get_mut(ent), )* $( $read.get(ent), )* ) {
-                        fun( $($write,)* $($read,)* );
+                        fun( $($write,)* $($read,)* $(&*$res,)* );
+                    }
+                }
+            });
+        }
+    })
+}
+
+impl_run!( run0w1r [] [R0] [] );
+impl_run!( run0w2r [] [R0, R1] [] );
+impl_run!( run1w0r [W0] [] [] );
+impl_run!( run1w1r [W0] [R0] [] );
+impl_run!( run1w2r [W0] [R0, R1] [] );
+impl_run!( run1w3r [W0] [R0, R1, R2] [] );
+impl_run!( run1w4r [W0] [R0, R1, R2, R3] [] );
+impl_run!( run1w5r [W0] [R0, R1, R2, R3, R4] [] );
+impl_run!( run1w6r [W0] [R0, R1, R2, R3, R4, R5] [] );
+impl_run!( run1w7r [W0] [R0, R1, R2, R3, R5, R6, R7] [] );
+impl_run!( run2w0r [W0, W1] [] [] );
+impl_run!( run2w1r [W0, W1] [R0] [] );
+impl_run!( run2w2r [W0, W1] [R0, R1] [] );
+
+impl_run!( run1w0r1res [W0] [] [X0] );
+impl_run!( run1w1r1res [W0] [R0] [X0] );
+impl_run!( run1w2r1res [W0] [R0, R1] [X0] );
+
+macro_rules! impl_run_local {
+    ($name:ident [$( $write:ident ),*] [$( $read:ident ),*]) => (impl Scheduler {
+        /// Like the `run*` family, but for `LocalComponent` writes. Runs
+        /// synchronously on this thread instead of being handed to the
+        /// `ThreadPool`, after waiting for every in-flight system to
+        /// finish, so it never races a `ThreadPool` system over a shared
+        /// `$read` component. Sees entities added earlier this frame by
+        /// another system (still pending in the appendix until `rest`),
+        /// same as `run*` does through `WorldArg::new_entities`.
+        #[allow(non_snake_case, unused_mut)]
+        pub fn $name<
+            $($write: LocalComponent,)* $($read: Component,)*
+            F: FnMut( $(&mut $write,)* $(&$read,)* )
+        >(&mut self, mut functor: F) {
+            self.flush();
+            let live: Vec<Entity> = {
+                let pending = self.appendix.read();
+                self.world.entities().chain(pending.add_queue.iter().cloned()).collect()
+            };
+            $( let $write = self.lock_local::<$write>(); )*
+            $( let mut $write = $write.borrow_mut(); )*
+            $( let $read = self.world.read::<$read>(); )*
+            for ent in live {
+                if let ( $( Some($write), )* $( Some($read), )* ) =
+                    ( $( $write.get_mut(ent), )* $( $read.get(ent), )* ) {
+                    functor( $($write,)* $($read,)* );
+                }
+            }
+        }
+    })
+}
+
+impl_run_local!( run_local1w0r [W0] [] );
+impl_run_local!( run_local1w1r [W0] [R0] );
+impl_run_local!( run_local2w0r [W0, W1] [] );
+
+macro_rules! impl_par_run {
+    ($name:ident [$( $write:ident ),*] [$( $read:ident ),*]) => (impl Scheduler {
+        /// Like `run*`, but splits the live entities into disjoint
+        /// index-range chunks and fans `functor` out across `self.threads`
+        /// (the scheduler's own `ThreadPool`, not newly spawned OS
+        /// threads). Only components backed by `VecStorage` can be
+        /// written this way: each write storage's backing vector is cut
+        /// with `split_at_mut` into one genuinely non-overlapping slice
+        /// per chunk, so a worker's `&mut` borrow of its slice can never
+        /// alias another worker's, unlike indexing through a pointer
+        /// shared across the whole storage.
+        #[allow(non_snake_case)]
+        pub fn $name<
+            $($write: Component<Storage = VecStorage<$write>>,)* $($read: Component,)*
+            F: 'static + Send + Sync + Fn( $(&mut $write,)* $(&$read,)* )
+        >(&mut self, functor: F) {
+            let access = Access::with_sets(
+                vec![ $( TypeId::of::<$write>(), )* ],
+                vec![ $( TypeId::of::<$read>(), )* ],
+            );
+            let num_chunks = self.threads.max_count().max(1);
+            let pool = self.threads.clone();
+            self.dispatch(access, move |warg| {
+                let ($(mut $write,)* $($read,)* entities) = warg.fetch(|w|
+                    ($(w.write::<$write>(),)*
+                     $(w.read::<$read>(),)*
+                       w.entities())
+                );
+                let live: Vec<Entity> = entities.chain(warg.new_entities()).collect();
+                if live.is_empty() {
+                    return;
+                }
+                let max_id = live.iter().map(|e| e.get_id()).max().unwrap() + 1;
+                let chunk_size = ((max_id + num_chunks - 1) / num_chunks).max(1);
+                let num_actual_chunks = (max_id + chunk_size - 1) / chunk_size;
+
+                $(
+                    $write.ensure_len(max_id);
+                    let $write: Vec<RawPtr<[Option<$write>]>> = {
+                        let mut rest = $write.as_mut_slice();
+                        let mut pieces = Vec::with_capacity(num_actual_chunks);
+                        while !rest.is_empty() {
+                            let at = chunk_size.min(rest.len());
+                            let (head, tail) = rest.split_at_mut(at);
+                            pieces.push(RawPtr(head as *mut [Option<$write>]));
+                            rest = tail;
+                        }
+                        pieces
+                    };
+                )*
+                $( let $read = RawPtr(&*$read as *const <$read as Component>::Storage as *mut <$read as Component>::Storage); )*
+
+                // The outer closure itself is already occupying one of
+                // `pool`'s worker threads (via `dispatch`), so handing
+                // every chunk to `pool.execute` and then waiting on them
+                // would deadlock a 1-thread (or fully saturated) pool: the
+                // only worker able to run a queued chunk is the one
+                // blocked waiting for it. Dodge that by running the last
+                // chunk inline on this thread and only fanning the rest
+                // out to the pool.
+                let last = num_actual_chunks - 1;
+                let pending = Arc::new((Mutex::new(last), Condvar::new()));
+                let fun = Arc::new(functor);
+
+                for k in 0..last {
+                    let start = k * chunk_size;
+                    let chunk_entities: Vec<Entity> = live.iter().cloned()
+                        .filter(|e| e.get_id() >= start && e.get_id() < start + chunk_size)
+                        .collect();
+                    $( let $write = $write[k]; )*
+                    $( let $read = $read; )*
+                    let fun = fun.clone();
+                    let pending = pending.clone();
+                    pool.execute(move || {
+                        $( let $write: &mut [Option<$write>] = unsafe { &mut *$write.0 }; )*
+                        $( let $read: &<$read as Component>::Storage = unsafe { &*$read.0 }; )*
+                        for &ent in &chunk_entities {
+                            let idx = ent.get_id() - start;
+                            if let ( $( Some($write), )* $( Some($read), )* ) =
+                                ( $( $write[idx].as_mut(), )* $( $read.get(ent), )* ) {
+                                fun( $($write,)* $($read,)* );
+                            }
+                        }
+                        let &(ref lock, ref cvar) = &*pending;
+                        let mut count = lock.lock();
+                        *count -= 1;
+                        if *count == 0 {
+                            cvar.notify_all();
+                        }
+                    });
+                }
+
+                {
+                    let start = last * chunk_size;
+                    let chunk_entities: Vec<Entity> = live.iter().cloned()
+                        .filter(|e| e.get_id() >= start && e.get_id() < start + chunk_size)
+                        .collect();
+                    $( let $write = $write[last]; )*
+                    $( let $write: &mut [Option<$write>] = unsafe { &mut *$write.0 }; )*
+                    $( let $read: &<$read as Component>::Storage = unsafe { &*$read.0 }; )*
+                    for &ent in &chunk_entities {
+                        let idx = ent.get_id() - start;
+                        if let ( $( Some($write), )* $( Some($read), )* ) =
+                            ( $( $write[idx].as_mut(), )* $( $read.get(ent), )* ) {
+                            fun( $($write,)* $($read,)* );
+                        }
                     }
                 }
+
+                let &(ref lock, ref cvar) = &*pending;
+                let mut count = lock.lock();
+                while *count > 0 {
+                    cvar.wait(&mut count);
+                }
             });
         }
     })
 }
 
-impl_run!( run0w1r [] [R0] );
-impl_run!( run0w2r [] [R0, R1] );
-impl_run!( run1w0r [W0] [] );
-impl_run!( run1w1r [W0] [R0] );
-impl_run!( run1w2r [W0] [R0, R1] );
-impl_run!( run1w3r [W0] [R0, R1, R2] );
-impl_run!( run1w4r [W0] [R0, R1, R2, R3] );
-impl_run!( run1w5r [W0] [R0, R1, R2, R3, R4] );
-impl_run!( run1w6r [W0] [R0, R1, R2, R3, R4, R5] );
-impl_run!( run1w7r [W0] [R0, R1, R2, R3, R5, R6, R7] );
-impl_run!( run2w0r [W0, W1] [] );
-impl_run!( run2w1r [W0, W1] [R0] );
-impl_run!( run2w2r [W0, W1] [R0, R1] );
\ No newline at end of file
+/// A raw pointer handed to a `par_run*` worker, carrying no lifetime so it
+/// can cross the `'static` bound `ThreadPool::execute` requires. Sound
+/// because the `par_run*` body waits on its chunk counter until every
+/// worker using one of these has finished, and only then drops the storage
+/// guards they point into, so the pointee always outlives its use; mutable pointers are
+/// additionally only ever produced by `split_at_mut`, so distinct workers
+/// never hold overlapping ones.
+struct RawPtr<T: ?Sized>(*mut T);
+impl<T: ?Sized> Clone for RawPtr<T> {
+    fn clone(&self) -> RawPtr<T> { RawPtr(self.0) }
+}
+impl<T: ?Sized> Copy for RawPtr<T> {}
+unsafe impl<T: ?Sized> Send for RawPtr<T> {}
+unsafe impl<T: ?Sized> Sync for RawPtr<T> {}
+
+impl_par_run!( par_run1w0r [W0] [] );
+impl_par_run!( par_run1w1r [W0] [R0] );
+impl_par_run!( par_run1w2r [W0] [R0, R1] );
+impl_par_run!( par_run2w1r [W0, W1] [R0] );
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Pos(i32);
+    impl Component for Pos { type Storage = VecStorage<Pos>; }
+
+    #[test]
+    fn access_conflicts_on_shared_write() {
+        let a = Access::with_sets(vec![TypeId::of::<Pos>()], vec![]);
+        let b = Access::with_sets(vec![TypeId::of::<Pos>()], vec![]);
+        assert!(a.conflicts(&b));
+    }
+
+    #[test]
+    fn access_shared_reads_do_not_conflict() {
+        let a = Access::with_sets(vec![], vec![TypeId::of::<Pos>()]);
+        let b = Access::with_sets(vec![], vec![TypeId::of::<Pos>()]);
+        assert!(!a.conflicts(&b));
+    }
+
+    #[test]
+    fn access_write_conflicts_with_read() {
+        let a = Access::with_sets(vec![TypeId::of::<Pos>()], vec![]);
+        let b = Access::with_sets(vec![], vec![TypeId::of::<Pos>()]);
+        assert!(a.conflicts(&b));
+    }
+
+    #[test]
+    fn access_exclusive_conflicts_with_everything() {
+        let a = Access::exclusive();
+        let b = Access::with_sets(vec![], vec![]);
+        assert!(a.conflicts(&b));
+        assert!(b.conflicts(&a));
+    }
+
+    #[test]
+    fn scheduler_flush_waits_for_dispatched_writes() {
+        let mut world = World::new();
+        world.register::<Pos>();
+        let mut sched = Scheduler::new(world, 2);
+        sched.add_entity().with(Pos(1)).build();
+        sched.rest();
+
+        sched.run1w0r(|pos: &mut Pos| { pos.0 += 41; });
+        sched.flush();
+
+        let world = sched.get_world();
+        let positions = world.read::<Pos>();
+        let total: i32 = world.entities().map(|e| positions.get(e).unwrap().0).sum();
+        assert_eq!(total, 42);
+    }
+
+    struct DeltaTime(f32);
+
+    #[test]
+    fn resource_read_write_round_trip() {
+        let world = World::new();
+        world.add_resource(DeltaTime(0.5));
+        assert_eq!(world.read_resource::<DeltaTime>().0, 0.5);
+
+        world.write_resource::<DeltaTime>().0 = 1.5;
+        assert_eq!(world.read_resource::<DeltaTime>().0, 1.5);
+    }
+
+    #[test]
+    fn resource_system_reads_resource_alongside_components() {
+        let mut world = World::new();
+        world.register::<Pos>();
+        world.add_resource(DeltaTime(2.0));
+        let mut sched = Scheduler::new(world, 1);
+        sched.add_entity().with(Pos(1)).build();
+        sched.rest();
+
+        sched.run1w0r1res(|pos: &mut Pos, dt: &DeltaTime| {
+            pos.0 = (pos.0 as f32 * dt.0) as i32;
+        });
+        sched.flush();
+
+        let world = sched.get_world();
+        let positions = world.read::<Pos>();
+        let total: i32 = world.entities().map(|e| positions.get(e).unwrap().0).sum();
+        assert_eq!(total, 2);
+    }
+
+    #[cfg(feature = "serde")]
+    #[derive(Serialize, Deserialize)]
+    struct Score(i32);
+    #[cfg(feature = "serde")]
+    impl Component for Score { type Storage = VecStorage<Score>; }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn save_load_round_trip_clears_stale_components() {
+        let mut world = World::new();
+        world.register_serializable::<Score>("score");
+        let mut sched = Scheduler::new(world, 1);
+
+        let e1 = sched.add_entity().with(Score(10)).build();
+        sched.rest();
+        let snapshot = sched.get_world().save();
+
+        let e2 = sched.add_entity().with(Score(99)).build();
+        sched.rest();
+
+        sched.get_world().load(snapshot);
+
+        let world = sched.get_world();
+        let scores = world.read::<Score>();
+        assert_eq!(scores.get(e1).map(|s| s.0), Some(10));
+        assert_eq!(scores.get(e2).map(|s| s.0), None);
+    }
+
+    struct Vel(i32);
+    impl Component for Vel { type Storage = VecStorage<Vel>; }
+
+    #[test]
+    fn par_run_applies_writes_across_chunks() {
+        let mut world = World::new();
+        world.register::<Pos>();
+        world.register::<Vel>();
+        let mut sched = Scheduler::new(world, 4);
+        for i in 0..37 {
+            sched.add_entity().with(Pos(0)).with(Vel(i)).build();
+        }
+        sched.rest();
+
+        sched.par_run1w1r(|pos: &mut Pos, vel: &Vel| {
+            pos.0 += vel.0;
+        });
+        sched.flush();
+
+        let world = sched.get_world();
+        let positions = world.read::<Pos>();
+        let total: i32 = world.entities().map(|e| positions.get(e).unwrap().0).sum();
+        let expected: i32 = (0..37).sum();
+        assert_eq!(total, expected);
+    }
+
+    #[test]
+    fn par_run_does_not_deadlock_on_a_single_threaded_pool() {
+        let mut world = World::new();
+        world.register::<Pos>();
+        world.register::<Vel>();
+        let mut sched = Scheduler::new(world, 1);
+        for i in 0..5 {
+            sched.add_entity().with(Pos(0)).with(Vel(i)).build();
+        }
+        sched.rest();
+
+        // With only one pool thread, that thread is already spent running
+        // this closure (via `dispatch`), so every chunk must still make
+        // progress without depending on a second worker being free.
+        sched.par_run1w1r(|pos: &mut Pos, vel: &Vel| {
+            pos.0 += vel.0;
+        });
+        sched.flush();
+
+        let world = sched.get_world();
+        let positions = world.read::<Pos>();
+        let total: i32 = world.entities().map(|e| positions.get(e).unwrap().0).sum();
+        let expected: i32 = (0..5).sum();
+        assert_eq!(total, expected);
+    }
+
+    #[test]
+    fn try_write_returns_none_while_a_read_guard_is_held() {
+        let mut world = World::new();
+        world.register::<Pos>();
+        let _reader = world.read::<Pos>();
+        assert!(world.try_write::<Pos>().is_none());
+    }
+
+    #[test]
+    fn try_read_returns_none_while_a_write_guard_is_held() {
+        let mut world = World::new();
+        world.register::<Pos>();
+        let _writer = world.write::<Pos>();
+        assert!(world.try_read::<Pos>().is_none());
+    }
+
+    #[test]
+    fn try_read_and_try_write_succeed_when_unlocked() {
+        let mut world = World::new();
+        world.register::<Pos>();
+        assert!(world.try_read::<Pos>().is_some());
+        assert!(world.try_write::<Pos>().is_some());
+    }
+
+    struct Tag(::std::rc::Rc<::std::cell::Cell<i32>>);
+    impl LocalComponent for Tag { type Storage = VecStorage<Tag>; }
+
+    #[test]
+    fn run_local_mutates_non_send_component_and_sees_new_entities() {
+        let world = World::new();
+        let mut sched = Scheduler::new(world, 1);
+        sched.register_local::<Tag>();
+
+        let e1 = sched.add_entity().build();
+        sched.rest();
+        sched.lock_local::<Tag>().borrow_mut()
+            .insert(e1, Tag(::std::rc::Rc::new(::std::cell::Cell::new(1))));
+
+        // e2 is added after the last `rest`; `Scheduler::add_entity`
+        // writes its generation straight away, so run_local must see it
+        // alongside e1.
+        let e2 = sched.add_entity().build();
+        sched.lock_local::<Tag>().borrow_mut()
+            .insert(e2, Tag(::std::rc::Rc::new(::std::cell::Cell::new(10))));
+
+        let mut seen = Vec::new();
+        sched.run_local1w0r(|tag: &mut Tag| {
+            seen.push(tag.0.get());
+        });
+
+        seen.sort();
+        assert_eq!(seen, vec![1, 10]);
+    }
+}
\ No newline at end of file
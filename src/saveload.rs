@@ -0,0 +1,71 @@
+//! World snapshotting, gated behind the `serde` feature. A `TypeId` isn't
+//! stable across builds, so components are keyed by the name they were
+//! passed to `World::register_serializable` rather than their type.
+
+use std::collections::HashMap;
+use std::marker::PhantomData;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use {Component, Entity, Generation, World};
+use storage::Storage;
+
+/// A full, format-agnostic copy of a `World`'s entity generations and every
+/// serializable component's values. `World::save`/`World::load` produce and
+/// consume these; the `Snapshot` itself serializes with serde like any
+/// other value, so it can be written out as JSON, bincode, or whatever the
+/// caller prefers.
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    pub(crate) generations: Vec<Generation>,
+    pub(crate) components: HashMap<String, Vec<(Entity, Value)>>,
+}
+
+/// Object-safe serialization hook for one registered component type.
+/// `World` keeps one of these per name passed to `register_serializable`,
+/// since the concrete `Storage<T>` behind it can't be named outside the
+/// generic context it was registered in.
+pub trait SerializeStorage: Send + Sync {
+    /// Collect every live instance of this component into `(Entity, Value)`
+    /// pairs.
+    fn save(&self, world: &World) -> Vec<(Entity, Value)>;
+    /// Replace this component's storage with the previously saved values,
+    /// discarding whatever it held before.
+    fn load(&self, world: &World, values: Vec<(Entity, Value)>);
+}
+
+// `fn(T)` is Send + Sync for any `T`, so this marker carries no bound on T
+// itself while still tying `ComponentSerializer` to the component type.
+struct ComponentSerializer<T>(PhantomData<fn(T)>);
+
+impl<T> SerializeStorage for ComponentSerializer<T>
+    where T: Component, T::Storage: Storage<T>, T: Serialize + DeserializeOwned
+{
+    fn save(&self, world: &World) -> Vec<(Entity, Value)> {
+        let storage = world.read::<T>();
+        world.entities()
+            .filter_map(|e| storage.get(e).map(|c|
+                (e, ::serde_json::to_value(c).expect("component failed to serialize"))
+            ))
+            .collect()
+    }
+    fn load(&self, world: &World, values: Vec<(Entity, Value)>) {
+        let mut storage = world.write::<T>();
+        *storage = T::Storage::new();
+        for (entity, value) in values {
+            let component: T = ::serde_json::from_value(value)
+                .expect("component failed to deserialize");
+            storage.insert(entity, component);
+        }
+    }
+}
+
+/// Build the `SerializeStorage` for a component type, used by
+/// `World::register_serializable`.
+pub fn serializer<T>() -> Box<SerializeStorage>
+    where T: Component, T::Storage: Storage<T>, T: Serialize + DeserializeOwned
+{
+    Box::new(ComponentSerializer::<T>(PhantomData))
+}
@@ -0,0 +1,358 @@
+use std::marker::PhantomData;
+use std::mem;
+use std::u32;
+
+use fnv::FnvHashMap;
+use {Entity, Index};
+
+/// Abstract storage, without knowledge of the component type held within.
+/// This is what lets `World` keep storages behind a single `TypeId`-keyed
+/// map and still drop an entity's component wherever it happens to live.
+pub trait StorageBase {
+    /// Remove the component belonging to `entity`, if any.
+    fn del(&mut self, entity: Entity);
+    /// Clear any pending change-detection events. A no-op for storages
+    /// that don't track changes; `FlaggedStorage` is the one that does.
+    fn clear_flags(&mut self) {}
+}
+
+/// Backing storage for a component type `T`.
+pub trait Storage<T>: StorageBase {
+    /// Create an empty storage.
+    fn new() -> Self;
+    /// Add or replace the component for `entity`.
+    fn insert(&mut self, entity: Entity, value: T);
+    /// Borrow the component for `entity`, if it has one.
+    fn get(&self, entity: Entity) -> Option<&T>;
+    /// Mutably borrow the component for `entity`, if it has one.
+    fn get_mut(&mut self, entity: Entity) -> Option<&mut T>;
+}
+
+/// Dense vector storage, indexed directly by entity index. Fast and simple,
+/// at the cost of a slot for every entity up to the highest index in use,
+/// whether or not that entity actually has the component.
+pub struct VecStorage<T> {
+    data: Vec<Option<T>>,
+}
+
+impl<T> StorageBase for VecStorage<T> {
+    fn del(&mut self, entity: Entity) {
+        if let Some(slot) = self.data.get_mut(entity.get_id()) {
+            *slot = None;
+        }
+    }
+}
+
+impl<T> Storage<T> for VecStorage<T> {
+    fn new() -> VecStorage<T> {
+        VecStorage { data: Vec::new() }
+    }
+    fn insert(&mut self, entity: Entity, value: T) {
+        let id = entity.get_id();
+        while self.data.len() <= id {
+            self.data.push(None);
+        }
+        self.data[id] = Some(value);
+    }
+    fn get(&self, entity: Entity) -> Option<&T> {
+        self.data.get(entity.get_id()).and_then(|slot| slot.as_ref())
+    }
+    fn get_mut(&mut self, entity: Entity) -> Option<&mut T> {
+        self.data.get_mut(entity.get_id()).and_then(|slot| slot.as_mut())
+    }
+}
+
+impl<T> VecStorage<T> {
+    /// Grow the backing vector to at least `len` slots, same as `insert`
+    /// does incrementally. Used by `par_run*` to pad every write storage
+    /// to a common length before splitting it into per-chunk slices.
+    pub(crate) fn ensure_len(&mut self, len: usize) {
+        while self.data.len() < len {
+            self.data.push(None);
+        }
+    }
+    /// Raw access to the backing slots, indexed directly by entity index.
+    /// Used by `par_run*` to split the storage into genuinely disjoint,
+    /// non-overlapping slices with `split_at_mut` rather than handing out
+    /// overlapping `&mut` borrows of the whole storage.
+    pub(crate) fn as_mut_slice(&mut self) -> &mut [Option<T>] {
+        &mut self.data
+    }
+}
+
+/// Sparse map storage, keyed by entity index. Suited to components only a
+/// small fraction of entities carry, where a `VecStorage` would waste space.
+pub struct HashMapStorage<T> {
+    data: FnvHashMap<Index, T>,
+}
+
+impl<T> StorageBase for HashMapStorage<T> {
+    fn del(&mut self, entity: Entity) {
+        self.data.remove(&(entity.get_id() as Index));
+    }
+}
+
+impl<T> Storage<T> for HashMapStorage<T> {
+    fn new() -> HashMapStorage<T> {
+        HashMapStorage { data: FnvHashMap::default() }
+    }
+    fn insert(&mut self, entity: Entity, value: T) {
+        self.data.insert(entity.get_id() as Index, value);
+    }
+    fn get(&self, entity: Entity) -> Option<&T> {
+        self.data.get(&(entity.get_id() as Index))
+    }
+    fn get_mut(&mut self, entity: Entity) -> Option<&mut T> {
+        self.data.get_mut(&(entity.get_id() as Index))
+    }
+}
+
+/// Sentinel recorded in `SparseSetStorage::sparse` for an entity index that
+/// currently holds no component.
+const ABSENT: u32 = u32::MAX;
+
+/// Sparse-set storage: `VecStorage`'s O(1) access with `HashMapStorage`'s
+/// frugality, and `dense_data` is kept dense and cache-friendly internally.
+///
+/// `sparse[entity.index]` holds the position of that entity's component in
+/// the dense arrays (or `ABSENT`). `dense_entities` and `dense_data` are
+/// kept in lock-step, so `dense_entities[i]` names the entity that owns
+/// `dense_data[i]`. Removal swap-removes from both dense arrays and patches
+/// `sparse` for whichever entity got swapped into the vacated slot.
+///
+/// Note `impl_run!` doesn't know about `dense_data` yet: it still walks
+/// `World::entities()` and calls `get`/`get_mut` per entity regardless of
+/// which `Storage` backs a component, so a system over a sparse component
+/// still probes every live entity instead of iterating `dense_data`
+/// directly. Wiring that up needs `impl_run!` to pick its iteration order
+/// from the smallest involved storage, which is a bigger change than this
+/// type alone; this storage gives correct O(1) access today, not yet the
+/// dense-iteration speedup.
+pub struct SparseSetStorage<T> {
+    sparse: Vec<u32>,
+    dense_entities: Vec<Index>,
+    dense_data: Vec<T>,
+}
+
+impl<T> SparseSetStorage<T> {
+    /// Dense-array position of `index`'s component, if it is actually
+    /// present (validated by cross-checking `dense_entities`, not just the
+    /// `ABSENT` sentinel, so a stale `sparse` entry can never alias another
+    /// entity's slot).
+    fn position(&self, index: Index) -> Option<usize> {
+        match self.sparse.get(index as usize) {
+            Some(&pos) if pos != ABSENT => {
+                if self.dense_entities.get(pos as usize) == Some(&index) {
+                    Some(pos as usize)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+impl<T> StorageBase for SparseSetStorage<T> {
+    fn del(&mut self, entity: Entity) {
+        let index = entity.get_id() as Index;
+        let pos = match self.position(index) {
+            Some(pos) => pos,
+            None => return,
+        };
+        self.dense_data.swap_remove(pos);
+        self.dense_entities.swap_remove(pos);
+        self.sparse[index as usize] = ABSENT;
+        if let Some(&moved) = self.dense_entities.get(pos) {
+            self.sparse[moved as usize] = pos as u32;
+        }
+    }
+}
+
+impl<T> Storage<T> for SparseSetStorage<T> {
+    fn new() -> SparseSetStorage<T> {
+        SparseSetStorage {
+            sparse: Vec::new(),
+            dense_entities: Vec::new(),
+            dense_data: Vec::new(),
+        }
+    }
+    fn insert(&mut self, entity: Entity, value: T) {
+        let index = entity.get_id() as Index;
+        while self.sparse.len() <= index as usize {
+            self.sparse.push(ABSENT);
+        }
+        if let Some(pos) = self.position(index) {
+            self.dense_data[pos] = value;
+            return;
+        }
+        self.sparse[index as usize] = self.dense_data.len() as u32;
+        self.dense_entities.push(index);
+        self.dense_data.push(value);
+    }
+    fn get(&self, entity: Entity) -> Option<&T> {
+        self.position(entity.get_id() as Index).map(|pos| &self.dense_data[pos])
+    }
+    fn get_mut(&mut self, entity: Entity) -> Option<&mut T> {
+        match self.position(entity.get_id() as Index) {
+            Some(pos) => self.dense_data.get_mut(pos),
+            None => None,
+        }
+    }
+}
+
+/// A change recorded by a `FlaggedStorage`, naming which entity it
+/// happened to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ComponentEvent {
+    /// The entity's component was inserted (including a replacing insert
+    /// over an existing one).
+    Inserted(Entity),
+    /// The entity's component was locked for writing via `get_mut`. Fired
+    /// whenever a mutable borrow is handed out, whether or not the caller
+    /// actually changed anything.
+    Modified(Entity),
+    /// The entity's component was removed.
+    Removed(Entity),
+}
+
+/// Wraps another `Storage<T>` and records an `Inserted`/`Modified`/`Removed`
+/// event every time an entity's component changes, so systems can react to
+/// just what changed instead of scanning every entity each tick. Note that
+/// `Modified` fires on every `get_mut` that finds a component, whether or
+/// not the caller actually writes through the reference it returns, since
+/// the underlying `Storage<T>::get_mut` signature gives no way to tell the
+/// two apart.
+pub struct FlaggedStorage<T, S: Storage<T> = VecStorage<T>> {
+    storage: S,
+    events: Vec<ComponentEvent>,
+    marker: PhantomData<T>,
+}
+
+impl<T, S: Storage<T>> FlaggedStorage<T, S> {
+    /// Take the events recorded since the last `drain_events` or
+    /// `clear_flags`, leaving the buffer empty.
+    pub fn drain_events(&mut self) -> Vec<ComponentEvent> {
+        mem::replace(&mut self.events, Vec::new())
+    }
+    /// Discard any pending events without returning them.
+    pub fn clear_flags(&mut self) {
+        self.events.clear();
+    }
+}
+
+impl<T, S: Storage<T>> StorageBase for FlaggedStorage<T, S> {
+    fn del(&mut self, entity: Entity) {
+        if self.storage.get(entity).is_some() {
+            self.storage.del(entity);
+            self.events.push(ComponentEvent::Removed(entity));
+        }
+    }
+    fn clear_flags(&mut self) {
+        FlaggedStorage::clear_flags(self);
+    }
+}
+
+impl<T, S: Storage<T>> Storage<T> for FlaggedStorage<T, S> {
+    fn new() -> FlaggedStorage<T, S> {
+        FlaggedStorage {
+            storage: S::new(),
+            events: Vec::new(),
+            marker: PhantomData,
+        }
+    }
+    fn insert(&mut self, entity: Entity, value: T) {
+        self.storage.insert(entity, value);
+        self.events.push(ComponentEvent::Inserted(entity));
+    }
+    fn get(&self, entity: Entity) -> Option<&T> {
+        self.storage.get(entity)
+    }
+    fn get_mut(&mut self, entity: Entity) -> Option<&mut T> {
+        let found = self.storage.get_mut(entity);
+        if found.is_some() {
+            self.events.push(ComponentEvent::Modified(entity));
+        }
+        found
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ent(id: u32) -> Entity {
+        Entity::new(id, 1)
+    }
+
+    #[test]
+    fn sparse_set_insert_get() {
+        let mut s: SparseSetStorage<u32> = Storage::new();
+        s.insert(ent(3), 30);
+        s.insert(ent(0), 0);
+        assert_eq!(s.get(ent(3)), Some(&30));
+        assert_eq!(s.get(ent(0)), Some(&0));
+        assert_eq!(s.get(ent(1)), None);
+    }
+
+    #[test]
+    fn sparse_set_del_swap_remove_preserves_others() {
+        let mut s: SparseSetStorage<u32> = Storage::new();
+        s.insert(ent(0), 0);
+        s.insert(ent(1), 1);
+        s.insert(ent(2), 2);
+        s.del(ent(0));
+        assert_eq!(s.get(ent(0)), None);
+        assert_eq!(s.get(ent(1)), Some(&1));
+        assert_eq!(s.get(ent(2)), Some(&2));
+    }
+
+    #[test]
+    fn sparse_set_del_is_idempotent() {
+        let mut s: SparseSetStorage<u32> = Storage::new();
+        s.insert(ent(5), 50);
+        s.del(ent(5));
+        s.del(ent(5));
+        assert_eq!(s.get(ent(5)), None);
+    }
+
+    #[test]
+    fn sparse_set_reinsert_replaces_value() {
+        let mut s: SparseSetStorage<u32> = Storage::new();
+        s.insert(ent(2), 20);
+        s.insert(ent(2), 21);
+        assert_eq!(s.get(ent(2)), Some(&21));
+    }
+
+    #[test]
+    fn flagged_storage_records_insert_modify_remove() {
+        let mut s: FlaggedStorage<u32, VecStorage<u32>> = Storage::new();
+        let e0 = ent(0);
+
+        s.insert(e0, 10);
+        assert_eq!(s.drain_events(), vec![ComponentEvent::Inserted(e0)]);
+
+        if let Some(v) = s.get_mut(e0) {
+            *v += 1;
+        }
+        assert_eq!(s.drain_events(), vec![ComponentEvent::Modified(e0)]);
+
+        s.del(e0);
+        assert_eq!(s.drain_events(), vec![ComponentEvent::Removed(e0)]);
+    }
+
+    #[test]
+    fn flagged_storage_del_of_absent_entity_emits_nothing() {
+        let mut s: FlaggedStorage<u32, VecStorage<u32>> = Storage::new();
+        s.del(ent(1));
+        assert_eq!(s.drain_events(), vec![]);
+    }
+
+    #[test]
+    fn flagged_storage_clear_flags_discards_pending_events() {
+        let mut s: FlaggedStorage<u32, VecStorage<u32>> = Storage::new();
+        s.insert(ent(0), 1);
+        s.clear_flags();
+        assert_eq!(s.drain_events(), vec![]);
+    }
+}